@@ -0,0 +1,175 @@
+/*
+ * Copyright © 2018-2019, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::result;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitflags::bitflags;
+
+use crate::errors::XcpError;
+use crate::operations::Reflink;
+
+bitflags! {
+    /// Classes of source metadata to restore on the destination,
+    /// mirroring `cp --preserve=...`. Parsed from a comma-separated
+    /// list via `FromStr`.
+    #[derive(Default)]
+    pub struct Preserve: u8 {
+        const MODE       = 0b0001;
+        const OWNERSHIP  = 0b0010;
+        const TIMESTAMPS = 0b0100;
+        const XATTR      = 0b1000;
+    }
+}
+
+impl FromStr for Preserve {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut preserve = Preserve::empty();
+        for part in s.split(',') {
+            match part.trim() {
+                "mode" => preserve |= Preserve::MODE,
+                "ownership" => preserve |= Preserve::OWNERSHIP,
+                "timestamps" => preserve |= Preserve::TIMESTAMPS,
+                "xattr" => preserve |= Preserve::XATTR,
+                "all" => preserve = Preserve::all(),
+                "" => {}
+                other => return Err(XcpError::InvalidArguments(
+                    format!("Unexpected value for 'preserve': {}", other))),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Size of data-copy blocks.
+    pub block_size: u64,
+    /// Number of parallel workers to use; 0 means auto-detect from
+    /// available parallelism.
+    pub workers: usize,
+    /// Metadata classes to restore on copied files and directories.
+    pub preserve: Preserve,
+    /// fsync each file after it is written.
+    pub fsync: bool,
+    /// Attempt to use copy-on-write reflinks where supported.
+    pub reflink: Reflink,
+    /// Error out rather than overwrite an existing destination file.
+    pub no_clobber: bool,
+    /// Treat an existing `dest` as the literal target rather than a
+    /// parent directory to copy into.
+    pub no_target_directory: bool,
+    /// Honour .gitignore files found while walking sources.
+    pub gitignore: bool,
+    /// Skip destination files that already match the source on size
+    /// and mtime, and resume destination files that are shorter than
+    /// the source (e.g. from an interrupted prior run).
+    pub update: bool,
+    /// Stricter variant of `update`: when size matches but mtime
+    /// differs, compare file contents rather than assuming they
+    /// differ.
+    pub verify: bool,
+    /// Whether the tree walker should materialise directories on
+    /// disk as it encounters them. Always true for drivers that copy
+    /// into a real destination tree; the archive driver disables
+    /// this since `dest` is a single archive file, not a directory.
+    pub materialize_dirs: bool,
+    /// LZMA2 dictionary/window size used by the archive driver when
+    /// writing a `.tar.xz`. Defaults to a large window for better
+    /// compression ratio on big trees, at the cost of more memory.
+    pub archive_dict_size: u32,
+    /// Minimum time between forwarded progress updates, regardless
+    /// of how many block-size boundaries are crossed in between.
+    pub progress_interval_ms: u64,
+}
+
+impl Config {
+    pub fn num_workers(&self) -> usize {
+        if self.workers > 0 {
+            self.workers
+        } else {
+            num_cpus::get()
+        }
+    }
+
+    pub fn progress_interval(&self) -> Duration {
+        Duration::from_millis(self.progress_interval_ms)
+    }
+
+    /// Whether a destination that looks like the product of a prior,
+    /// interrupted run (unchanged-skip, short-file resume) should be
+    /// considered. `verify` is a stricter variant of `update`, not a
+    /// wholly independent mode, so either flag enables this.
+    pub fn resumable(&self) -> bool {
+        self.update || self.verify
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            block_size: 1024 * 1024,
+            workers: 0,
+            preserve: Preserve::MODE,
+            fsync: false,
+            reflink: Reflink::default(),
+            no_clobber: false,
+            no_target_directory: false,
+            gitignore: true,
+            update: false,
+            verify: false,
+            materialize_dirs: true,
+            archive_dict_size: 64 * 1024 * 1024,
+            progress_interval_ms: 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preserve_parses_individual_classes() {
+        assert_eq!("mode".parse::<Preserve>().unwrap(), Preserve::MODE);
+        assert_eq!("ownership".parse::<Preserve>().unwrap(), Preserve::OWNERSHIP);
+        assert_eq!("timestamps".parse::<Preserve>().unwrap(), Preserve::TIMESTAMPS);
+        assert_eq!("xattr".parse::<Preserve>().unwrap(), Preserve::XATTR);
+    }
+
+    #[test]
+    fn preserve_parses_comma_list() {
+        let preserve: Preserve = "mode,timestamps".parse().unwrap();
+        assert!(preserve.contains(Preserve::MODE));
+        assert!(preserve.contains(Preserve::TIMESTAMPS));
+        assert!(!preserve.contains(Preserve::OWNERSHIP));
+        assert!(!preserve.contains(Preserve::XATTR));
+    }
+
+    #[test]
+    fn preserve_all_sets_every_class() {
+        let preserve: Preserve = "all".parse().unwrap();
+        assert_eq!(preserve, Preserve::all());
+    }
+
+    #[test]
+    fn preserve_rejects_unknown_class() {
+        assert!("bogus".parse::<Preserve>().is_err());
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * Copyright © 2018-2019, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Write the walked source tree into a single tar archive rather
+//! than materialising files under `dest`. Selected via the `archive`
+//! driver; `dest` ending in `.tar.xz` additionally wraps the stream
+//! in an LZMA2 encoder.
+
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crossbeam_channel as cbc;
+use log::{debug, warn};
+use tar::{Builder, EntryType, Header};
+use xz2::stream::{Check, Filters, LzmaOptions, Stream};
+use xz2::write::XzEncoder;
+
+use crate::config::{Config, Preserve};
+use crate::drivers::CopyDriver;
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+use crate::operations::{Operation, tree_walker};
+
+pub struct Driver {
+    config: Arc<Config>,
+}
+
+impl Driver {
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        Ok(Self { config })
+    }
+}
+
+impl CopyDriver for Driver {
+    fn copy_all(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        // `dest` here is the single archive file, not a tree to copy
+        // into, so `--no-clobber` is checked against it directly
+        // rather than via the per-entry check in `tree_walker` (which
+        // only applies when `materialize_dirs` roots entries under
+        // the real destination).
+        if self.config.no_clobber && dest.exists() {
+            return Err(XcpError::DestinationExists(
+                "Destination file exists and --no-clobber is set.",
+                dest.to_path_buf(),
+            ).into());
+        }
+
+        let (work_tx, work_rx) = cbc::unbounded();
+        let dirs = Arc::new(Mutex::new(Vec::new()));
+
+        // Set up the output file/encoder before spawning the walker,
+        // so a failure here (e.g. `dest` not creatable, xz encoder
+        // setup failing) returns early without ever having started a
+        // thread that would then go unjoined.
+        let out = File::create(dest)?;
+        let writer = if is_xz(dest) {
+            let opts = LzmaOptions::new_preset(9)
+                .map_err(|e| XcpError::CopyError(format!("Failed to configure xz encoder: {}", e)))?;
+            let mut opts = opts;
+            opts.dict_size(self.config.archive_dict_size);
+            let mut filters = Filters::new();
+            filters.lzma2(&opts);
+            let stream = Stream::new_stream_encoder(&filters, Check::Crc64)
+                .map_err(|e| XcpError::CopyError(format!("Failed to start xz encoder: {}", e)))?;
+            ArchiveWriter::Xz(XzEncoder::new_stream(out, stream))
+        } else {
+            ArchiveWriter::Plain(out)
+        };
+
+        // The walker has no real destination tree to create
+        // directories under; directory entries come back over the
+        // channel instead.
+        let mut walk_config = (*self.config).clone();
+        walk_config.materialize_dirs = false;
+        let walk_config = Arc::new(walk_config);
+
+        let walk_worker = {
+            let sc = stats.clone();
+            let d = dest.to_path_buf();
+            let o = walk_config.clone();
+            thread::spawn(move || tree_walker(sources, &d, &o, work_tx, sc, dirs))
+        };
+
+        let write_result = write_archive(writer, work_rx, &self.config, stats);
+
+        // Always join the walker, even if `write_archive` already
+        // failed, so its thread isn't leaked; prefer surfacing its
+        // error since it's the usual root cause (e.g. a --no-clobber
+        // abort) when `write_archive` only sees the work channel dry
+        // up early.
+        let walk_result = walk_worker.join()
+            .map_err(|_| XcpError::CopyError("Error during tree walk".to_string()))?;
+        walk_result?;
+        write_result?;
+
+        Ok(())
+    }
+
+    fn copy_single(&self, source: &Path, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        self.copy_all(vec![source.to_path_buf()], dest, stats)
+    }
+}
+
+fn is_xz(dest: &Path) -> bool {
+    dest.to_string_lossy().ends_with(".tar.xz")
+}
+
+/// The archive's underlying output, as either a plain file or an xz
+/// stream wrapping one. Kept as a concrete enum rather than a generic
+/// `Builder<W: Write>` so that `write_archive` can explicitly call
+/// `XzEncoder::finish` and propagate any error from flushing the
+/// final LZMA2 block and end-of-stream marker, instead of leaving
+/// that to the encoder's `Drop` impl, which discards it.
+enum ArchiveWriter {
+    Plain(File),
+    Xz(XzEncoder<File>),
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveWriter::Plain(w) => w.write(buf),
+            ArchiveWriter::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(w) => w.flush(),
+            ArchiveWriter::Xz(w) => w.flush(),
+        }
+    }
+}
+
+fn write_archive(
+    writer: ArchiveWriter,
+    work_rx: cbc::Receiver<Operation>,
+    config: &Config,
+    stats: Arc<dyn StatusUpdater>,
+) -> Result<()> {
+    let mut builder = Builder::new(writer);
+    let preserve = config.preserve;
+
+    for op in work_rx {
+        debug!("Archiving operation {:?}", op);
+        match op {
+            Operation::Copy(from, to) => {
+                let mut infd = File::open(&from)?;
+                let meta = infd.metadata()?;
+                let mut header = Header::new_gnu();
+                header.set_size(meta.len());
+                stamp_header(&mut header, &meta, preserve);
+                header.set_cksum();
+                builder.append_data(&mut header, &to, ChunkedReader {
+                    inner: &mut infd,
+                    block_size: config.block_size,
+                    stats: stats.clone(),
+                })?;
+            }
+
+            Operation::Link(from, to) => {
+                // `from` is already the symlink's resolved target
+                // text (set via `read_link` in `tree_walker`), not a
+                // path on disk to read back; use it directly.
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                header.set_mode(0o777);
+                header.set_cksum();
+                builder.append_link(&mut header, &to, &from)?;
+            }
+
+            Operation::Dir(from, to) => {
+                let meta = from.metadata()?;
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                stamp_header(&mut header, &meta, preserve);
+                header.set_cksum();
+                builder.append_data(&mut header, &to, io::empty())?;
+            }
+
+            Operation::Special(from, _to) => {
+                warn!("Archive driver does not support special files; skipping {:?}", from);
+            }
+        }
+    }
+
+    match builder.into_inner()? {
+        ArchiveWriter::Plain(mut f) => f.flush()?,
+        ArchiveWriter::Xz(enc) => {
+            enc.finish()
+                .map_err(|e| XcpError::CopyError(format!("Failed to finish xz stream: {}", e)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Stamp the metadata classes selected by `config.preserve` onto a
+/// tar header, the same way `CopyHandle::finalise_copy` gates mode,
+/// ownership and timestamps individually rather than copying
+/// everything unconditionally.
+fn stamp_header(header: &mut Header, meta: &Metadata, preserve: Preserve) {
+    if preserve.contains(Preserve::MODE) {
+        header.set_mode(meta.mode() & 0o7777);
+    }
+    if preserve.contains(Preserve::OWNERSHIP) {
+        header.set_uid(meta.uid() as u64);
+        header.set_gid(meta.gid() as u64);
+    }
+    if preserve.contains(Preserve::TIMESTAMPS) {
+        header.set_mtime(meta.mtime() as u64);
+    }
+}
+
+/// Bridges `Read` onto the same `block_size`-chunked reads the other
+/// drivers use, rather than handing the tar builder an unbounded
+/// direct copy. Also reports each chunk read as a `Copied` update, the
+/// same way `CopyHandle::copy_bytes` does for the other driver, so
+/// progress/rate reporting advances while the archive is streamed
+/// rather than sitting at the `Size` total reported during the walk.
+struct ChunkedReader<'a> {
+    inner: &'a mut File,
+    block_size: u64,
+    stats: Arc<dyn StatusUpdater>,
+}
+
+impl<'a> Read for ChunkedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = std::cmp::min(buf.len() as u64, self.block_size) as usize;
+        let n = self.inner.read(&mut buf[..limit])?;
+        if n > 0 {
+            let _ = self.stats.send(StatusUpdate::Copied(n as u64));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xz2::read::XzDecoder;
+
+    use crate::operations::NoopUpdater;
+
+    #[test]
+    fn is_xz_detects_tar_xz_suffix() {
+        assert!(is_xz(Path::new("out.tar.xz")));
+        assert!(is_xz(Path::new("/some/dir/backup.tar.xz")));
+    }
+
+    #[test]
+    fn is_xz_rejects_plain_tar() {
+        assert!(!is_xz(Path::new("out.tar")));
+        assert!(!is_xz(Path::new("out.tar.gz")));
+    }
+
+    #[test]
+    fn copy_all_rejects_existing_dest_under_no_clobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a.txt"), b"a").unwrap();
+
+        // The archive file itself already exists at `dest`; this is
+        // the path that actually risks being clobbered, unlike the
+        // bare relative in-archive entry names `tree_walker` would
+        // otherwise check.
+        let dest = dir.path().join("out.tar");
+        std::fs::write(&dest, b"existing archive").unwrap();
+
+        let mut config = Config::default();
+        config.no_clobber = true;
+        let driver = Driver::new(Arc::new(config)).unwrap();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        let err = driver.copy_all(vec![source], &dest, stats)
+            .expect_err("copy_all should refuse to overwrite an existing archive under --no-clobber");
+        assert!(err.to_string().contains("no-clobber"));
+        assert_eq!(std::fs::read(&dest).unwrap(), b"existing archive",
+            "the existing archive must not be touched");
+    }
+
+    fn single_copy_op(source: &Path, to: &str) -> cbc::Receiver<Operation> {
+        let (work_tx, work_rx) = cbc::unbounded();
+        work_tx.send(Operation::Copy(source.to_path_buf(), PathBuf::from(to))).unwrap();
+        drop(work_tx);
+        work_rx
+    }
+
+    #[test]
+    fn write_archive_plain_tar_round_trips_file_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("hello.txt");
+        std::fs::write(&source, b"hello archive").unwrap();
+        let work_rx = single_copy_op(&source, "hello.txt");
+
+        let dest = dir.path().join("out.tar");
+        let out = File::create(&dest).unwrap();
+        let config = Config::default();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        write_archive(ArchiveWriter::Plain(out), work_rx, &config, stats).unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&dest).unwrap());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().into_owned(), PathBuf::from("hello.txt"));
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello archive");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn write_archive_tar_xz_round_trips_file_contents_and_finishes_the_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("hello.txt");
+        std::fs::write(&source, b"hello xz archive").unwrap();
+        let work_rx = single_copy_op(&source, "hello.txt");
+
+        let dest = dir.path().join("out.tar.xz");
+        let out = File::create(&dest).unwrap();
+        let encoder = XzEncoder::new(out, 6);
+        let config = Config::default();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        write_archive(ArchiveWriter::Xz(encoder), work_rx, &config, stats).unwrap();
+
+        // Reading the tar entries back out of the decompressed stream
+        // only works if the LZMA2 end-of-stream marker was actually
+        // flushed by `write_archive`, not left to `Drop`.
+        let mut archive = tar::Archive::new(XzDecoder::new(File::open(&dest).unwrap()));
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello xz archive");
+    }
+}
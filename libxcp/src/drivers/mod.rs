@@ -0,0 +1,58 @@
+/*
+ * Copyright © 2018-2019, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+pub mod parfile;
+pub mod archive;
+
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+use crate::feedback::StatusUpdater;
+
+pub trait CopyDriver {
+    fn copy_all(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()>;
+    fn copy_single(&self, source: &Path, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()>;
+}
+
+
+#[derive(Debug, Clone)]
+pub enum Drivers {
+    ParFile,
+    Archive,
+}
+
+impl FromStr for Drivers {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "parfile" => Ok(Drivers::ParFile),
+            "archive" => Ok(Drivers::Archive),
+            _ => Err(XcpError::UnknownDriver { driver: s.to_owned() }.into()),
+        }
+    }
+}
+
+pub fn load_driver(driver: &Drivers, config: &Arc<Config>) -> Result<Box<dyn CopyDriver>> {
+    match driver {
+        Drivers::ParFile => Ok(Box::new(parfile::Driver::new(config.clone())?)),
+        Drivers::Archive => Ok(Box::new(archive::Driver::new(config.clone())?)),
+    }
+}
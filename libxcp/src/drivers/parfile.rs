@@ -23,14 +23,14 @@ use libfs::{copy_node, FileType};
 use std::fs::remove_file;
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use crate::config::Config;
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
 use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
+use crate::operations::{CopyHandle, Operation, finalise_dirs, tree_walker};
 
 // ********************************************************************** //
 
@@ -49,15 +49,17 @@ impl Driver {
 impl CopyDriver for Driver {
     fn copy_all(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
         let (work_tx, work_rx) = cbc::unbounded();
+        let dirs = Arc::new(Mutex::new(Vec::new()));
 
         // Thread which walks the file tree and sends jobs to the
         // workers. The worker tx channel is moved to the walker so it is
         // closed, which will cause the workers to shutdown on completion.
-        let _walk_worker = {
+        let walk_worker = {
             let sc = stats.clone();
             let d = dest.to_path_buf();
             let o = self.config.clone();
-            thread::spawn(move || tree_walker(sources, &d, &o, work_tx, sc))
+            let dirs = dirs.clone();
+            thread::spawn(move || tree_walker(sources, &d, &o, work_tx, sc, dirs))
         };
 
         // Worker threads. Will consume work and then shutdown once the
@@ -79,6 +81,11 @@ impl CopyDriver for Driver {
                 .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
         }
 
+        walk_worker.join()
+            .map_err(|_| XcpError::CopyError("Error during tree walk".to_string()))??;
+
+        finalise_dirs(&dirs.lock().unwrap(), &self.config)?;
+
         Ok(())
     }
 
@@ -161,6 +168,12 @@ fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Ar
                 copy_node(&from, &to)?;
             }
 
+            Operation::Dir(_, _) => {
+                // Materialised by the walker itself for this driver;
+                // only emitted as a worker operation by drivers (e.g.
+                // archive) that disable `config.materialize_dirs`.
+            }
+
         }
     }
     debug!("Copy worker {:?} shutting down", thread::current().id());
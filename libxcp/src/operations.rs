@@ -15,21 +15,28 @@
  */
 
 use std::{cmp, thread};
-use std::fs::{File, Metadata, read_link, create_dir_all};
+use std::fs::{File, Metadata, OpenOptions, read_link, create_dir_all};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 use crossbeam_channel as cbc;
+use ignore::{WalkBuilder, WalkState};
 use libfs::{
     allocate_file, copy_file_bytes, copy_permissions, next_sparse_segments, probably_sparse, sync, reflink, FileType,
 };
 use log::{debug, error};
-use walkdir::WalkDir;
+use nix::sys::stat::futimens;
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchown, lseek, Gid, Uid, Whence};
 
-use crate::config::Config;
+use crate::config::{Config, Preserve};
 use crate::errors::{Result, XcpError};
 use crate::paths::{parse_ignore, ignore_filter};
 
@@ -62,6 +69,10 @@ pub struct CopyHandle {
     pub outfd: File,
     pub metadata: Metadata,
     pub config: Arc<Config>,
+    /// Byte offset both descriptors are already positioned at; set
+    /// when resuming a destination left short by an interrupted
+    /// prior run.
+    resume_offset: u64,
 }
 
 impl CopyHandle {
@@ -69,14 +80,62 @@ impl CopyHandle {
         let infd = File::open(from)?;
         let metadata = infd.metadata()?;
 
-        let outfd = File::create(to)?;
-        allocate_file(&outfd, metadata.len())?;
+        // In --update/--verify mode, a destination that is shorter
+        // than the source *and* corroborated by an older mtime looks
+        // like the tail-end of a prior, interrupted run: resume from
+        // where it left off instead of reallocating and rewriting it
+        // from scratch. Length alone isn't enough evidence: an
+        // unrelated, newer file that happens to be shorter must not
+        // have its prefix kept and its tail overwritten with the
+        // source's tail, which would silently stitch together a
+        // corrupted hybrid of the two. Also gated on `materialize_dirs`:
+        // `to` is only a real destination path for drivers that
+        // materialise a tree (see the equivalent guard in
+        // `tree_walker`'s `unchanged()` call).
+        let dest_meta = if config.resumable() && config.materialize_dirs { to.metadata().ok() } else { None };
+        let resume_offset = match dest_meta {
+            Some(ref dm) if dm.len() < metadata.len() && dest_older_than_source(dm, &metadata) => {
+                let offset = dm.len();
+                // --verify is stricter still: confirm the resident
+                // prefix's bytes actually match the source's
+                // corresponding bytes before trusting it, rather than
+                // relying on mtime alone.
+                if config.verify && offset > 0 && !prefix_matches(from, to, offset, config.block_size)? {
+                    0
+                } else {
+                    offset
+                }
+            }
+            _ => 0,
+        };
+
+        let outfd = if resume_offset > 0 {
+            let outfd = OpenOptions::new().write(true).open(to)?;
+            lseek(infd.as_raw_fd(), resume_offset as i64, Whence::SeekSet)?;
+            lseek(outfd.as_raw_fd(), resume_offset as i64, Whence::SeekSet)?;
+            outfd
+        } else {
+            let outfd = File::create(to)?;
+            if !config.resumable() {
+                // Pre-allocate the full extent up front; `copy_sparse`
+                // depends on the destination already having its full
+                // length (see `next_sparse_segments`, below). Skipped
+                // in --update/--verify mode so a destination killed
+                // mid-copy is left short of `metadata.len()` and so
+                // picked up by the resume check above on the next
+                // run, rather than looking like a complete file to
+                // recopy from scratch.
+                allocate_file(&outfd, metadata.len())?;
+            }
+            outfd
+        };
 
         let handle = CopyHandle {
             infd,
             outfd,
             metadata,
             config: config.clone(),
+            resume_offset,
         };
 
         Ok(handle)
@@ -133,10 +192,28 @@ impl CopyHandle {
     }
 
     pub fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        if self.resume_offset > 0 {
+            // Descriptors are already positioned at resume_offset;
+            // the remaining range is a plain contiguous copy. Report
+            // the already-resident bytes up-front so progress/ETA
+            // accounting reflects the full file size.
+            updates.send(StatusUpdate::Copied(self.resume_offset))?;
+            let remaining = self.metadata.len() - self.resume_offset;
+            let copied = self.copy_bytes(remaining, updates)?;
+            return Ok(self.resume_offset + copied);
+        }
+
         if self.try_reflink()? {
             return Ok(self.metadata.len());
         }
-        let total = if probably_sparse(&self.infd)? {
+
+        // In --update/--verify mode the destination isn't pre-allocated
+        // to its full extent (see `CopyHandle::new`), so there is no
+        // full SEEK_HOLE/SEEK_DATA extent on the output for
+        // `copy_sparse` to compare against; always do a plain
+        // contiguous copy so the file stays naturally resumable if
+        // interrupted.
+        let total = if !self.config.resumable() && probably_sparse(&self.infd)? {
             self.copy_sparse(&updates)?
         } else {
             self.copy_bytes(self.metadata.len(), &updates)?
@@ -146,9 +223,24 @@ impl CopyHandle {
     }
 
     fn finalise_copy(&self) -> Result<()> {
-        if !self.config.no_perms {
+        let preserve = self.config.preserve;
+
+        // Ownership is restored before mode: `fchown` clears the
+        // setuid/setgid bits (POSIX-mandated), so applying it after
+        // `copy_permissions` would silently strip those bits back off
+        // again on a source that has them set.
+        if preserve.contains(Preserve::OWNERSHIP) {
+            preserve_ownership(&self.metadata, &self.outfd)?;
+        }
+        if preserve.contains(Preserve::MODE) {
             copy_permissions(&self.infd, &self.outfd)?;
         }
+        if preserve.contains(Preserve::TIMESTAMPS) {
+            preserve_timestamps(&self.metadata, &self.outfd)?;
+        }
+        if preserve.contains(Preserve::XATTR) {
+            preserve_xattrs(&self.infd, &self.outfd)?;
+        }
         if self.config.fsync {
             debug!("Syncing file {:?}", self.outfd);
             sync(&self.outfd)?;
@@ -157,6 +249,72 @@ impl CopyHandle {
     }
 }
 
+/// Restore the owning uid/gid of `meta` onto `outfd`, mirroring
+/// `cp`'s behaviour of silently skipping this when not privileged
+/// enough to chown (rather than failing the whole copy).
+fn preserve_ownership(meta: &Metadata, outfd: &File) -> Result<()> {
+    let uid = Uid::from_raw(meta.uid());
+    let gid = Gid::from_raw(meta.gid());
+    match fchown(outfd.as_raw_fd(), Some(uid), Some(gid)) {
+        Ok(()) => Ok(()),
+        Err(nix::errno::Errno::EPERM) => {
+            debug!("Insufficient privileges to preserve ownership of {:?}; skipping", outfd);
+            Ok(())
+        }
+        Err(e) => Err(XcpError::CopyError(format!("Failed to preserve ownership: {}", e)).into()),
+    }
+}
+
+/// Restore the source mtime/atime onto `outfd`.
+fn preserve_timestamps(meta: &Metadata, outfd: &File) -> Result<()> {
+    let atime = TimeSpec::new(meta.atime(), meta.atime_nsec());
+    let mtime = TimeSpec::new(meta.mtime(), meta.mtime_nsec());
+    futimens(outfd.as_raw_fd(), &atime, &mtime)?;
+    Ok(())
+}
+
+/// Enumerate and re-set extended attributes from `infd` to `outfd`,
+/// skipping, like `preserve_ownership` does when not privileged, rather
+/// than failing the whole copy: a filesystem without xattr support is
+/// skipped.
+fn preserve_xattrs(infd: &File, outfd: &File) -> Result<()> {
+    let attrs = match xattr::list(infd) {
+        Ok(attrs) => attrs,
+        Err(e) if is_xattrs_unsupported(&e) => {
+            debug!("xattrs not supported on {:?}; skipping", infd);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    for attr in attrs {
+        let value = match xattr::get(infd, &attr) {
+            Ok(value) => value,
+            Err(e) if is_xattrs_unsupported(&e) => {
+                debug!("xattrs not supported on {:?}; skipping", infd);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let Some(value) = value else { continue };
+        if let Err(e) = xattr::set(outfd, &attr, &value) {
+            if is_xattrs_unsupported(&e) {
+                debug!("xattrs not supported on {:?}; skipping", outfd);
+                return Ok(());
+            }
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// True if `e` indicates the underlying filesystem doesn't support
+/// extended attributes at all (e.g. ENOTSUP), as opposed to a genuine
+/// I/O failure.
+fn is_xattrs_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+}
+
 impl Drop for CopyHandle {
     fn drop(&mut self) {
         // FIXME: SHould we chcek for panicking() here?
@@ -170,7 +328,12 @@ impl Drop for CopyHandle {
 pub enum StatusUpdate {
     Copied(u64),
     Size(u64),
-    Error(XcpError)
+    Error(XcpError),
+    /// Periodic rate/ETA summary, computed by `ChannelUpdater` from
+    /// the running copied-bytes counter and the total size seen via
+    /// `Size`, so front-ends don't each have to reimplement the
+    /// smoothing math.
+    Throughput { bytes_per_sec: f64, eta_secs: Option<f64> },
 }
 
 pub trait StatusUpdater: Sync + Send {
@@ -183,6 +346,9 @@ pub struct ChannelUpdater {
     chan_rx: cbc::Receiver<StatusUpdate>,
     config: Arc<Config>,
     sent: AtomicU64,
+    total: AtomicU64,
+    started: Instant,
+    last_sent: Mutex<Instant>,
 }
 
 impl ChannelUpdater {
@@ -193,6 +359,9 @@ impl ChannelUpdater {
             chan_rx,
             config: config.clone(),
             sent: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            started: Instant::now(),
+            last_sent: Mutex::new(Instant::now()),
         }
     }
 
@@ -213,20 +382,83 @@ impl ChannelUpdater {
     pub fn rx_channel(&self) -> cbc::Receiver<StatusUpdate> {
         self.chan_rx.clone()
     }
+
+    /// Whether enough wall-clock time has passed since the last
+    /// forwarded update that we should flush again even without a
+    /// block-size boundary being crossed. This keeps progress smooth
+    /// and bounded-frequency on both very fast (NVMe) and very slow
+    /// (network) destinations.
+    fn interval_elapsed(&self) -> bool {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if last_sent.elapsed() >= self.config.progress_interval() {
+            *last_sent = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reset the interval clock. Called when a `Copied` update is
+    /// forwarded on a block-boundary crossing (`interval_elapsed`
+    /// resets the clock itself when it fires), so that a
+    /// block-boundary flush also counts towards the interval and the
+    /// two triggers don't double-send.
+    fn mark_sent(&self) {
+        *self.last_sent.lock().unwrap() = Instant::now();
+    }
+
+    fn send_throughput(&self, written: u64) -> Result<()> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return Ok(());
+        }
+        let bytes_per_sec = written as f64 / elapsed;
+        let total = self.total.load(Ordering::Relaxed);
+        let eta_secs = if bytes_per_sec > 0.0 && total > written {
+            Some((total - written) as f64 / bytes_per_sec)
+        } else {
+            None
+        };
+        self.chan_tx.send(StatusUpdate::Throughput { bytes_per_sec, eta_secs })?;
+        Ok(())
+    }
 }
 
 impl StatusUpdater for ChannelUpdater {
-    // Wrapper around channel-send that groups updates together
+    // Wrapper around channel-send that groups updates together. Every
+    // `Copied` byte count is accounted immediately, but a `Copied`
+    // update is only actually forwarded once either a block-size
+    // boundary is crossed or the progress interval has elapsed since
+    // the last forwarded update, whichever comes first. Both triggers
+    // reset the interval clock (`mark_sent` for a block-boundary
+    // flush, `interval_elapsed` itself for an interval flush), rather
+    // than the two firing independently.
     fn send(&self, update: StatusUpdate) -> Result<()> {
-        if let StatusUpdate::Copied(bytes) = update {
-            // Avoid saturating the queue with small writes
-            let bsize = self.config.block_size;
-            let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
-            if ((prev_written + bytes) / bsize) > (prev_written / bsize) {
+        match update {
+            StatusUpdate::Copied(bytes) => {
+                let bsize = self.config.block_size;
+                let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
+                let written = prev_written + bytes;
+                let crossed_block = (written / bsize) > (prev_written / bsize);
+
+                if crossed_block {
+                    self.mark_sent();
+                    self.chan_tx.send(update)?;
+                    self.send_throughput(written)?;
+                } else if self.interval_elapsed() {
+                    self.chan_tx.send(update)?;
+                    self.send_throughput(written)?;
+                }
+            }
+
+            StatusUpdate::Size(bytes) => {
+                self.total.fetch_add(bytes, Ordering::Relaxed);
+                self.chan_tx.send(update)?;
+            }
+
+            _ => {
                 self.chan_tx.send(update)?;
             }
-        } else {
-            self.chan_tx.send(update)?;
         }
         Ok(())
     }
@@ -246,14 +478,25 @@ pub enum Operation {
     Copy(PathBuf, PathBuf),
     Link(PathBuf, PathBuf),
     Special(PathBuf, PathBuf),
+    /// A directory entry, emitted instead of being materialised
+    /// in-place when `config.materialize_dirs` is false (e.g. the
+    /// archive driver, which has no real destination tree to create
+    /// directories under).
+    Dir(PathBuf, PathBuf),
 }
 
+/// Walk the source trees and dispatch `Operation`s to the worker
+/// pool. The walk itself is parallelised via the `ignore` crate's
+/// work-stealing `WalkParallel`, using `config.num_workers()`
+/// walker threads, so traversal of deep/wide trees does not become
+/// a single-threaded bottleneck ahead of the copy workers.
 pub fn tree_walker(
     sources: Vec<PathBuf>,
     dest: &Path,
     config: &Config,
     work_tx: cbc::Sender<Operation>,
     stats: Arc<dyn StatusUpdater>,
+    dirs: Arc<Mutex<Vec<(PathBuf, PathBuf)>>>,
 ) -> Result<()> {
     debug!("Starting walk worker {:?}", thread::current().id());
 
@@ -263,68 +506,242 @@ pub fn tree_walker(
             .last()
             .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
 
-        let target_base = if dest.exists() && !config.no_target_directory {
+        let target_base = if !config.materialize_dirs {
+            // `dest` isn't a directory to copy into here (e.g. the
+            // archive driver's `dest` is the archive's own output
+            // file), so it must not be used as a naming prefix: root
+            // entry names at the source directory's own name instead.
+            PathBuf::from(&sourcedir)
+        } else if dest.exists() && !config.no_target_directory {
             dest.join(sourcedir)
         } else {
             dest.to_path_buf()
         };
         debug!("Target base is {:?}", target_base);
 
-        let gitignore = parse_ignore(&source, config)?;
-
-        for entry in WalkDir::new(&source)
-            .into_iter()
-            .filter_entry(|e| ignore_filter(e, &gitignore))
-        {
-            debug!("Got tree entry {:?}", entry);
-            let e = entry?;
-            let from = e.into_path();
-            let meta = from.symlink_metadata()?;
-            let path = from.strip_prefix(&source)?;
-            let target = if !empty_path(path) {
-                target_base.join(path)
-            } else {
-                target_base.clone()
-            };
-
-            if config.no_clobber && target.exists() {
-                let msg = "Destination file exists and --no-clobber is set.";
-                stats.send(StatusUpdate::Error(
-                    XcpError::DestinationExists(msg, target)))?;
-                return Err(XcpError::EarlyShutdown(msg).into());
-            }
+        let gitignore = Arc::new(parse_ignore(&source, config)?);
 
-            match FileType::from(meta.file_type()) {
-                FileType::File => {
-                    debug!("Send copy operation {:?} to {:?}", from, target);
-                    stats.send(StatusUpdate::Size(meta.len()))?;
-                    work_tx.send(Operation::Copy(from, target))?;
-                }
+        // Shared early-shutdown slot: any visitor thread can trip
+        // this (e.g. on `--no-clobber`) by storing the error that
+        // caused it, and every other thread will observe it and quit
+        // on its next entry. Carrying the actual error, rather than
+        // just a boolean, means `tree_walker`'s own `Result` reports
+        // what went wrong instead of a fixed generic message.
+        let early_shutdown: Arc<Mutex<Option<XcpError>>> = Arc::new(Mutex::new(None));
+
+        let walker = WalkBuilder::new(&source)
+            .threads(config.num_workers())
+            // The `ignore` crate's standard filters (hidden-file
+            // skipping, automatic .gitignore/.ignore/parent-directory
+            // honouring) are independent of `config.gitignore` and
+            // would silently change traversal defaults from the
+            // previous `WalkDir`-based walker. Disable them so the
+            // existing `parse_ignore`/`ignore_filter` gating remains
+            // the only filtering in effect.
+            .standard_filters(false)
+            .build_parallel();
 
-                FileType::Symlink => {
-                    let lfile = read_link(from)?;
-                    debug!("Send symlink operation {:?} to {:?}", lfile, target);
-                    work_tx.send(Operation::Link(lfile, target))?;
+        walker.run(|| {
+            let work_tx = work_tx.clone();
+            let stats = stats.clone();
+            let source = source.clone();
+            let target_base = target_base.clone();
+            let gitignore = gitignore.clone();
+            let config = config.clone();
+            let early_shutdown = early_shutdown.clone();
+            let dirs = dirs.clone();
+
+            Box::new(move |entry| {
+                if early_shutdown.lock().unwrap().is_some() {
+                    return WalkState::Quit;
                 }
 
-                FileType::Dir => {
-                    // Create dir tree immediately as we can't
-                    // guarantee a worker will action the creation
-                    // before a subsequent copy operation requires it.
-                    debug!("Creating target directory {:?}", target);
-                    create_dir_all(&target)?;
+                let e = match entry {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error!("Error during tree walk: {}", e);
+                        return WalkState::Continue;
+                    }
+                };
+                debug!("Got tree entry {:?}", e);
+
+                if !ignore_filter(&e, &gitignore) {
+                    // `Skip` (not `Continue`) is what actually prunes
+                    // recursion into a rejected directory; `Continue`
+                    // would still visit everything underneath it,
+                    // defeating e.g. a `.gitignore`d `target/` or
+                    // `node_modules/`. Harmless to return for a
+                    // rejected file too, since `Skip` only has
+                    // recursion semantics for directories.
+                    return WalkState::Skip;
                 }
 
-                FileType::Socket | FileType::Char | FileType::Fifo => {
-                    debug!("Special file found: {:?} to {:?}", from, target);
-                    work_tx.send(Operation::Special(from, target))?;
+                let from = e.into_path();
+                let meta = match from.symlink_metadata() {
+                    Ok(m) => m,
+                    Err(e) => {
+                        let _ = stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())));
+                        *early_shutdown.lock().unwrap() = Some(XcpError::CopyError(e.to_string()));
+                        return WalkState::Quit;
+                    }
+                };
+                let path = match from.strip_prefix(&source) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        let _ = stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())));
+                        *early_shutdown.lock().unwrap() = Some(XcpError::CopyError(e.to_string()));
+                        return WalkState::Quit;
+                    }
+                };
+                let target = if !empty_path(path) {
+                    target_base.join(path)
+                } else {
+                    target_base.clone()
+                };
+
+                let file_type = FileType::from(meta.file_type());
+
+                // For a regular file under --update/--verify, decide
+                // whether it's unchanged *before* the --no-clobber
+                // check below: --update's whole purpose is re-running
+                // a copy against a destination that already has
+                // content from a prior run, so an unchanged,
+                // already-copied file must be skipped rather than
+                // aborting the entire walk as a clobber conflict.
+                // Gated on `materialize_dirs` the same way the
+                // no-clobber check below is: drivers that disable it
+                // (e.g. the archive driver) give `target` as a bare
+                // in-archive entry name, not a path under the real
+                // destination, so comparing it against the
+                // filesystem would test an unrelated path.
+                if let FileType::File = file_type {
+                    if config.resumable() && config.materialize_dirs {
+                        match unchanged(&from, &target, &meta, &config) {
+                            Ok(true) => {
+                                debug!("Skipping unchanged destination {:?}", target);
+                                // Still account for the file's size so
+                                // progress/ETA totals match what
+                                // actually exists at the destination,
+                                // even though no bytes will be copied
+                                // for it.
+                                let _ = stats.send(StatusUpdate::Size(meta.len()));
+                                return WalkState::Continue;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                let _ = stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())));
+                                *early_shutdown.lock().unwrap() = Some(XcpError::CopyError(e.to_string()));
+                                return WalkState::Quit;
+                            }
+                        }
+                    }
                 }
 
-                FileType::Other => {
-                    error!("Unknown filetype found; this should never happen!");
-                    return Err(XcpError::UnknownFileType(target).into());
+                // `target` is only a real destination path to check
+                // here when `materialize_dirs` is set: drivers that
+                // disable it (e.g. the archive driver) use `target`
+                // purely as an in-archive entry name, rooted at the
+                // source directory's own name rather than under
+                // `dest`, so checking it against the filesystem would
+                // test an arbitrary path unrelated to the actual
+                // destination. Those drivers are responsible for
+                // their own `--no-clobber` check against `dest`
+                // itself instead. Under --update/--verify an existing
+                // directory (e.g. from a prior run) is not a conflict:
+                // it is merged into rather than overwritten.
+                let resumed_dir = config.resumable() && matches!(file_type, FileType::Dir);
+                if config.materialize_dirs && config.no_clobber && !resumed_dir && target.exists() {
+                    let msg = "Destination file exists and --no-clobber is set.";
+                    let _ = stats.send(StatusUpdate::Error(
+                        XcpError::DestinationExists(msg, target.clone())));
+                    *early_shutdown.lock().unwrap() = Some(XcpError::DestinationExists(msg, target));
+                    return WalkState::Quit;
                 }
-            };
+
+                match file_type {
+                    FileType::File => {
+                        debug!("Send copy operation {:?} to {:?}", from, target);
+                        if stats.send(StatusUpdate::Size(meta.len())).is_err()
+                            || work_tx.send(Operation::Copy(from, target)).is_err() {
+                            *early_shutdown.lock().unwrap() =
+                                Some(XcpError::CopyError("Work queue closed; aborting walk".to_string()));
+                            return WalkState::Quit;
+                        }
+                    }
+
+                    FileType::Symlink => {
+                        let lfile = match read_link(&from) {
+                            Ok(l) => l,
+                            Err(e) => {
+                                let _ = stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())));
+                                *early_shutdown.lock().unwrap() = Some(XcpError::CopyError(e.to_string()));
+                                return WalkState::Quit;
+                            }
+                        };
+                        debug!("Send symlink operation {:?} to {:?}", lfile, target);
+                        if work_tx.send(Operation::Link(lfile, target)).is_err() {
+                            *early_shutdown.lock().unwrap() =
+                                Some(XcpError::CopyError("Work queue closed; aborting walk".to_string()));
+                            return WalkState::Quit;
+                        }
+                    }
+
+                    FileType::Dir => {
+                        if config.materialize_dirs {
+                            // Create dir tree immediately as we can't
+                            // guarantee a worker will action the
+                            // creation before a subsequent copy
+                            // operation requires it. `create_dir_all`
+                            // is idempotent, so concurrent creation
+                            // from other walker threads is safe.
+                            debug!("Creating target directory {:?}", target);
+                            if let Err(e) = create_dir_all(&target) {
+                                let _ = stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())));
+                                *early_shutdown.lock().unwrap() = Some(XcpError::CopyError(e.to_string()));
+                                return WalkState::Quit;
+                            }
+                            // Metadata on a directory can only be
+                            // stamped once its children have been
+                            // written, so just record it here; the
+                            // driver re-applies preserved attributes
+                            // in a final pass once all copy workers
+                            // have joined.
+                            if !config.preserve.is_empty() {
+                                dirs.lock().unwrap().push((from, target));
+                            }
+                        } else {
+                            debug!("Send dir operation {:?} to {:?}", from, target);
+                            if work_tx.send(Operation::Dir(from, target)).is_err() {
+                                *early_shutdown.lock().unwrap() =
+                                    Some(XcpError::CopyError("Work queue closed; aborting walk".to_string()));
+                                return WalkState::Quit;
+                            }
+                        }
+                    }
+
+                    FileType::Socket | FileType::Char | FileType::Fifo => {
+                        debug!("Special file found: {:?} to {:?}", from, target);
+                        if work_tx.send(Operation::Special(from, target)).is_err() {
+                            *early_shutdown.lock().unwrap() =
+                                Some(XcpError::CopyError("Work queue closed; aborting walk".to_string()));
+                            return WalkState::Quit;
+                        }
+                    }
+
+                    FileType::Other => {
+                        error!("Unknown filetype found; this should never happen!");
+                        let _ = stats.send(StatusUpdate::Error(XcpError::UnknownFileType(target.clone())));
+                        *early_shutdown.lock().unwrap() = Some(XcpError::UnknownFileType(target));
+                        return WalkState::Quit;
+                    }
+                };
+
+                WalkState::Continue
+            })
+        });
+
+        if let Some(err) = early_shutdown.lock().unwrap().take() {
+            return Err(err.into());
         }
     }
     debug!("Walk-worker finished: {:?}", thread::current().id());
@@ -335,3 +752,532 @@ pub fn tree_walker(
 fn empty_path(path: &Path) -> bool {
     *path == PathBuf::new()
 }
+
+/// True if `target` already holds the same content as `source` and
+/// can be skipped under `--update`/`--verify`. A destination that is
+/// shorter than the source is never considered unchanged; it is
+/// instead picked up by `CopyHandle`'s resume path.
+fn unchanged(source: &Path, target: &Path, source_meta: &Metadata, config: &Config) -> Result<bool> {
+    let target_meta = match target.symlink_metadata() {
+        Ok(m) => m,
+        Err(_) => return Ok(false),
+    };
+
+    if target_meta.len() != source_meta.len() {
+        return Ok(false);
+    }
+
+    if target_meta.mtime() > source_meta.mtime()
+        || (target_meta.mtime() == source_meta.mtime() && target_meta.mtime_nsec() >= source_meta.mtime_nsec()) {
+        return Ok(true);
+    }
+
+    if config.verify {
+        return Ok(!files_differ(source, target, config.block_size)?);
+    }
+
+    Ok(false)
+}
+
+/// Stream-compare two equal-length files in `block_size` chunks,
+/// used by `--verify` to decide whether a same-size, older-mtime
+/// destination genuinely needs recopying.
+fn files_differ(source: &Path, target: &Path, block_size: u64) -> Result<bool> {
+    let mut a = File::open(source)?;
+    let mut b = File::open(target)?;
+    let block_size = cmp::max(block_size, 1) as usize;
+    let mut buf_a = vec![0u8; block_size];
+    let mut buf_b = vec![0u8; block_size];
+
+    loop {
+        let read_a = a.read(&mut buf_a)?;
+        let read_b = b.read(&mut buf_b)?;
+        if read_a != read_b {
+            return Ok(true);
+        }
+        if read_a == 0 {
+            return Ok(false);
+        }
+        if buf_a[..read_a] != buf_b[..read_b] {
+            return Ok(true);
+        }
+    }
+}
+
+/// True if `dest_meta` predates `source_meta`, the corroborating
+/// evidence `CopyHandle::new` requires (on top of a short length)
+/// before trusting a destination as the tail-end of a prior,
+/// interrupted run rather than some unrelated, newer file that just
+/// happens to be shorter.
+fn dest_older_than_source(dest_meta: &Metadata, source_meta: &Metadata) -> bool {
+    dest_meta.mtime() < source_meta.mtime()
+        || (dest_meta.mtime() == source_meta.mtime() && dest_meta.mtime_nsec() < source_meta.mtime_nsec())
+}
+
+/// True if the first `len` bytes of `source` and `target` are
+/// identical, used by `--verify` to confirm a short destination's
+/// resident prefix genuinely came from `source` before resuming past
+/// it, rather than trusting length and mtime alone.
+fn prefix_matches(source: &Path, target: &Path, len: u64, block_size: u64) -> Result<bool> {
+    let mut a = File::open(source)?;
+    let mut b = File::open(target)?;
+    let block_size = cmp::max(block_size, 1) as usize;
+    let mut buf_a = vec![0u8; block_size];
+    let mut buf_b = vec![0u8; block_size];
+
+    let mut remaining = len;
+    while remaining > 0 {
+        let to_read = cmp::min(remaining, block_size as u64) as usize;
+        a.read_exact(&mut buf_a[..to_read])?;
+        b.read_exact(&mut buf_b[..to_read])?;
+        if buf_a[..to_read] != buf_b[..to_read] {
+            return Ok(false);
+        }
+        remaining -= to_read as u64;
+    }
+
+    Ok(true)
+}
+
+/// Re-apply preserved attributes to directories after all their
+/// children have been copied. Called by the driver once the copy
+/// workers have joined.
+pub fn finalise_dirs(dirs: &[(PathBuf, PathBuf)], config: &Config) -> Result<()> {
+    let preserve = config.preserve;
+    if preserve.is_empty() {
+        return Ok(());
+    }
+
+    for (source, target) in dirs {
+        let infd = File::open(source)?;
+        let outfd = File::open(target)?;
+        let meta = infd.metadata()?;
+
+        // See `CopyHandle::finalise_copy`: ownership before mode, so
+        // `fchown` doesn't clear setuid/setgid bits `copy_permissions`
+        // just set.
+        if preserve.contains(Preserve::OWNERSHIP) {
+            preserve_ownership(&meta, &outfd)?;
+        }
+        if preserve.contains(Preserve::MODE) {
+            copy_permissions(&infd, &outfd)?;
+        }
+        if preserve.contains(Preserve::XATTR) {
+            preserve_xattrs(&infd, &outfd)?;
+        }
+        // Timestamps are applied last as writing children or other
+        // metadata above would otherwise bump the directory's mtime.
+        if preserve.contains(Preserve::TIMESTAMPS) {
+            preserve_timestamps(&meta, &outfd)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    fn write_file(path: &Path, contents: &[u8]) {
+        let mut f = File::create(path).unwrap();
+        f.write_all(contents).unwrap();
+    }
+
+    // Guards tests that need to temporarily change the process's CWD,
+    // which is otherwise shared (and thus racy) across the test
+    // binary's parallel test threads.
+    static CWD_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn files_differ_detects_identical_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        write_file(&a, b"the quick brown fox");
+        write_file(&b, b"the quick brown fox");
+
+        assert!(!files_differ(&a, &b, 4).unwrap());
+    }
+
+    #[test]
+    fn files_differ_detects_different_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        write_file(&a, b"the quick brown fox");
+        write_file(&b, b"the slow brown fox");
+
+        assert!(files_differ(&a, &b, 4).unwrap());
+    }
+
+    #[test]
+    fn unchanged_false_when_destination_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("missing");
+        write_file(&source, b"hello");
+
+        let meta = source.metadata().unwrap();
+        let config = Config::default();
+        assert!(!unchanged(&source, &target, &meta, &config).unwrap());
+    }
+
+    #[test]
+    fn unchanged_false_when_size_differs() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        write_file(&source, b"hello world");
+        write_file(&target, b"hi");
+
+        let meta = source.metadata().unwrap();
+        let config = Config::default();
+        assert!(!unchanged(&source, &target, &meta, &config).unwrap());
+    }
+
+    #[test]
+    fn unchanged_true_when_target_mtime_not_older() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        write_file(&source, b"hello");
+        write_file(&target, b"hello");
+        // Ensure target's mtime is at least as new as source's.
+        fs::File::open(&target).unwrap().set_modified(
+            source.metadata().unwrap().modified().unwrap()
+        ).unwrap();
+
+        let meta = source.metadata().unwrap();
+        let mut config = Config::default();
+        config.update = true;
+        assert!(unchanged(&source, &target, &meta, &config).unwrap());
+    }
+
+    #[test]
+    fn is_xattrs_unsupported_detects_unsupported_errorkind() {
+        let e = io::Error::new(io::ErrorKind::Unsupported, "xattrs not supported");
+        assert!(is_xattrs_unsupported(&e));
+    }
+
+    #[test]
+    fn is_xattrs_unsupported_rejects_other_errors() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert!(!is_xattrs_unsupported(&e));
+    }
+
+    #[test]
+    fn update_mode_does_not_preallocate_fresh_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        write_file(&source, b"0123456789");
+
+        let mut config = Config::default();
+        config.update = true;
+        let config = Arc::new(config);
+
+        // Simulates this process being killed partway through writing
+        // a fresh destination: the handle is dropped straight after
+        // creation, before any bytes are copied.
+        CopyHandle::new(&source, &target, &config).unwrap();
+
+        let len = target.metadata().unwrap().len();
+        assert!(len < source.metadata().unwrap().len(),
+            "destination should not be pre-allocated to the full source length in --update mode, got {}", len);
+    }
+
+    #[test]
+    fn update_mode_resumes_rather_than_recopies_killed_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        // A partial destination from a prior, interrupted run: the
+        // first 4 bytes are resident and genuinely came from copying
+        // `source`, with an mtime that predates it.
+        write_file(&target, b"0123");
+        fs::File::open(&target).unwrap().set_modified(
+            std::time::SystemTime::now() - std::time::Duration::from_secs(60)
+        ).unwrap();
+        write_file(&source, b"0123456789");
+
+        let mut config = Config::default();
+        config.update = true;
+        let config = Arc::new(config);
+
+        let handle = CopyHandle::new(&source, &target, &config).unwrap();
+        assert_eq!(handle.resume_offset, 4);
+
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        handle.copy_file(&stats).unwrap();
+        drop(handle);
+
+        let contents = fs::read(&target).unwrap();
+        assert_eq!(contents, b"0123456789", "resume should preserve the existing prefix and append only the missing tail");
+    }
+
+    #[test]
+    fn update_mode_does_not_resume_unrelated_newer_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        write_file(&source, b"0123456789");
+        // A shorter file that just happens to share the destination
+        // name and has a newer mtime than source: nothing ties it to
+        // a prior, interrupted run of this copy.
+        write_file(&target, b"XXXX");
+
+        let mut config = Config::default();
+        config.update = true;
+        let config = Arc::new(config);
+
+        let handle = CopyHandle::new(&source, &target, &config).unwrap();
+        assert_eq!(handle.resume_offset, 0,
+            "a shorter destination with a newer mtime must not be trusted as a resume point");
+
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        handle.copy_file(&stats).unwrap();
+        drop(handle);
+
+        let contents = fs::read(&target).unwrap();
+        assert_eq!(contents, b"0123456789",
+            "an untrusted short destination should be fully recopied, not stitched into a hybrid");
+    }
+
+    #[test]
+    fn verify_mode_recopies_when_resident_prefix_content_mismatches() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        // Older mtime alone would pass the --update corroboration
+        // check, but this prefix was never actually copied from
+        // `source`; --verify's content check should still catch it.
+        write_file(&target, b"XXXX");
+        fs::File::open(&target).unwrap().set_modified(
+            std::time::SystemTime::now() - std::time::Duration::from_secs(60)
+        ).unwrap();
+        write_file(&source, b"0123456789");
+
+        let mut config = Config::default();
+        config.verify = true;
+        let config = Arc::new(config);
+
+        let handle = CopyHandle::new(&source, &target, &config).unwrap();
+        assert_eq!(handle.resume_offset, 0,
+            "a mismatched resident prefix must not be trusted under --verify, even with a corroborating mtime");
+
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        handle.copy_file(&stats).unwrap();
+        drop(handle);
+
+        let contents = fs::read(&target).unwrap();
+        assert_eq!(contents, b"0123456789");
+    }
+
+    #[test]
+    fn channel_updater_throttles_copied_updates_by_interval() {
+        let mut config = Config::default();
+        config.progress_interval_ms = 60_000;
+        let config = Arc::new(config);
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+        for _ in 0..5 {
+            stats.send(StatusUpdate::Copied(1024)).unwrap();
+        }
+
+        assert!(rx.try_recv().is_err(), "no Copied update should be forwarded before the interval elapses");
+    }
+
+    #[test]
+    fn channel_updater_forwards_copied_once_interval_elapses() {
+        let mut config = Config::default();
+        config.progress_interval_ms = 0;
+        let config = Arc::new(config);
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+        stats.send(StatusUpdate::Copied(1024)).unwrap();
+
+        let saw_copied = rx.try_iter().any(|u| matches!(u, StatusUpdate::Copied(1024)));
+        assert!(saw_copied);
+    }
+
+    #[test]
+    fn channel_updater_forwards_copied_on_block_boundary() {
+        let mut config = Config::default();
+        // Long enough that `interval_elapsed` can't be what forwards this.
+        config.progress_interval_ms = 60_000;
+        config.block_size = 512;
+        let config = Arc::new(config);
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+        let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+
+        // A single update that crosses a block-size boundary should
+        // be forwarded immediately, regardless of the interval.
+        stats.send(StatusUpdate::Copied(600)).unwrap();
+
+        let saw_copied = rx.try_iter().any(|u| matches!(u, StatusUpdate::Copied(600)));
+        assert!(saw_copied, "crossing a block boundary should forward a Copied update even before the interval elapses");
+    }
+
+    /// Drain every `Operation::Copy` target queued by a `tree_walker`
+    /// run, relative to `dest`, for layout assertions below.
+    fn collect_copy_targets(rx: cbc::Receiver<Operation>, dest: &Path) -> Vec<PathBuf> {
+        let mut targets: Vec<PathBuf> = rx.try_iter()
+            .filter_map(|op| match op {
+                Operation::Copy(_, to) => Some(to.strip_prefix(dest).unwrap().to_path_buf()),
+                _ => None,
+            })
+            .collect();
+        targets.sort();
+        targets
+    }
+
+    #[test]
+    fn tree_walker_reproduces_source_layout_at_dest() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        write_file(&source.join("a.txt"), b"a");
+        write_file(&source.join("sub").join("b.txt"), b"b");
+
+        // Pre-create dest so the walker copies source *into* it
+        // (dest/src/...) rather than treating a non-existent dest as
+        // source renamed in place.
+        let dest = dir.path().join("out");
+        fs::create_dir_all(&dest).unwrap();
+        let mut config = Config::default();
+        config.workers = 1;
+        let (work_tx, work_rx) = cbc::unbounded();
+        let dirs = Arc::new(Mutex::new(Vec::new()));
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        tree_walker(vec![source], &dest, &config, work_tx, stats, dirs).unwrap();
+
+        assert!(dest.join("src").is_dir());
+        assert!(dest.join("src").join("sub").is_dir());
+
+        let targets = collect_copy_targets(work_rx, &dest);
+        assert_eq!(targets, vec![
+            PathBuf::from("src/a.txt"),
+            PathBuf::from("src/sub/b.txt"),
+        ]);
+    }
+
+    #[test]
+    fn tree_walker_aborts_whole_walk_on_no_clobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src");
+        fs::create_dir_all(&source).unwrap();
+        write_file(&source.join("a.txt"), b"a");
+        write_file(&source.join("b.txt"), b"b");
+
+        // Pre-create the dest layout with a.txt already present; the
+        // existing `out/src` target is itself a no-clobber conflict,
+        // so the walk aborts before queuing both files.
+        let dest = dir.path().join("out");
+        fs::create_dir_all(dest.join("src")).unwrap();
+        write_file(&dest.join("src").join("a.txt"), b"existing");
+
+        let mut config = Config::default();
+        config.workers = 1;
+        config.no_clobber = true;
+        let (work_tx, work_rx) = cbc::unbounded();
+        let dirs = Arc::new(Mutex::new(Vec::new()));
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        let err = tree_walker(vec![source], &dest, &config, work_tx, stats, dirs)
+            .expect_err("walk should abort once a no-clobber conflict is hit");
+        let msg = err.to_string();
+        assert!(msg.contains("no-clobber"), "error should identify the no-clobber conflict, got: {}", msg);
+        let targets = collect_copy_targets(work_rx, &dest);
+        assert!(targets.len() < 2, "walk should not have queued both files once it aborted, got {:?}", targets);
+    }
+
+    #[test]
+    fn tree_walker_skips_unchanged_file_under_update_and_no_clobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src");
+        fs::create_dir_all(&source).unwrap();
+        write_file(&source.join("a.txt"), b"a");
+
+        // Pre-create dest with a.txt already copied and up to date:
+        // the primary scenario --update exists for, re-running a
+        // copy against a destination from a prior run.
+        let dest = dir.path().join("out");
+        fs::create_dir_all(dest.join("src")).unwrap();
+        write_file(&dest.join("src").join("a.txt"), b"a");
+        fs::File::open(dest.join("src").join("a.txt")).unwrap().set_modified(
+            std::time::SystemTime::now() + std::time::Duration::from_secs(60)
+        ).unwrap();
+
+        let mut config = Config::default();
+        config.workers = 1;
+        config.update = true;
+        config.no_clobber = true;
+        let (work_tx, work_rx) = cbc::unbounded();
+        let dirs = Arc::new(Mutex::new(Vec::new()));
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        tree_walker(vec![source], &dest, &config, work_tx, stats, dirs)
+            .expect("an unchanged destination should be skipped, not treated as a clobber conflict");
+
+        let targets = collect_copy_targets(work_rx, &dest);
+        assert!(targets.is_empty(), "unchanged file should not be queued for copying, got {:?}", targets);
+    }
+
+    #[test]
+    fn tree_walker_does_not_check_filesystem_for_unchanged_when_not_materializing() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("src");
+        fs::create_dir_all(&source).unwrap();
+        write_file(&source.join("a.txt"), b"a");
+
+        // A non-materializing driver's `dest` (e.g. the archive
+        // driver's output file) isn't a directory to copy into;
+        // `target_base` ends up as the bare entry name "src", not a
+        // path under `dest`. If `unchanged()` were still consulted
+        // here, it would spuriously match this unrelated file that
+        // happens to share that relative name in the process's CWD.
+        // Scope the CWD change to this process-wide mutex so it can't
+        // race with another test's own CWD-relative assumptions.
+        let _cwd_guard = CWD_LOCK.lock().unwrap();
+        let original_cwd = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+
+        let unrelated = PathBuf::from("src");
+        fs::create_dir_all(&unrelated).unwrap();
+        write_file(&unrelated.join("a.txt"), b"a");
+        fs::File::open(unrelated.join("a.txt")).unwrap().set_modified(
+            std::time::SystemTime::now() + std::time::Duration::from_secs(60)
+        ).unwrap();
+
+        let dest = dir.path().join("archive.tar");
+        let mut config = Config::default();
+        config.workers = 1;
+        config.update = true;
+        config.materialize_dirs = false;
+        let (work_tx, work_rx) = cbc::unbounded();
+        let dirs = Arc::new(Mutex::new(Vec::new()));
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        let result = tree_walker(vec![source], &dest, &config, work_tx, stats, dirs);
+        std::env::set_current_dir(original_cwd).unwrap();
+        result.unwrap();
+
+        let targets: Vec<PathBuf> = work_rx.try_iter()
+            .filter_map(|op| match op {
+                Operation::Copy(_, to) => Some(to),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(targets, vec![PathBuf::from("src/a.txt")],
+            "the source file should still be queued for archiving, not skipped against an unrelated CWD-relative path");
+    }
+}